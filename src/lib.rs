@@ -1,102 +1,314 @@
 extern crate crypto;
 extern crate ipnetwork;
 
-use std::net::Ipv6Addr;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use crypto::blake2b::Blake2b;
 use crypto::digest::Digest;
-use ipnetwork::Ipv6Network;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 
-/// Generates an IPv6 address
+/// A specialised `Result` for address generation.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors that can occur while generating an address.
+#[derive(Debug)]
+pub enum Error {
+    /// The CIDR string could not be parsed into a network.
+    ParseNetwork(ipnetwork::IpNetworkError),
+    /// The prefix leaves no host bits — it is already a full address.
+    /// `subnet` also returns this when `new_prefix` is not longer than the
+    /// parent prefix or is greater than 128.
+    PrefixTooLong,
+    /// The octets we assembled did not form a valid address.
+    AddressConstruction,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ParseNetwork(ref err) => write!(f, "invalid CIDR: {}", err),
+            Error::PrefixTooLong => write!(f, "prefix is already a full address"),
+            Error::AddressConstruction => {
+                write!(f, "generated address failed to parse")
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::ParseNetwork(_) => "invalid CIDR",
+            Error::PrefixTooLong => "prefix is already a full address",
+            Error::AddressConstruction => "generated address failed to parse",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::ParseNetwork(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ipnetwork::IpNetworkError> for Error {
+    fn from(err: ipnetwork::IpNetworkError) -> Error {
+        Error::ParseNetwork(err)
+    }
+}
+
+/// Generates an IP address
 ///
-/// `ip6gen` takes any string and a unique IPv6 local address
-/// prefix eg `fd52:f6b0:3162::/64` and computes a unique IP address.
-pub fn ip(name: &str, cidr: &str) -> Result<Ipv6Addr, String> {
-    let net = match Ipv6Network::from_str(cidr).map_err(|err| format!("{:?}", err)) {
-        Ok(net) => {
+/// `ip` takes any string and an IP address prefix in CIDR notation,
+/// eg `fd52:f6b0:3162::/64` or `10.0.0.0/8`, and computes a unique
+/// address inside that network. The address family is derived from the
+/// prefix, so an IPv4 CIDR yields an `Ipv4Addr` and an IPv6 CIDR an
+/// `Ipv6Addr`, both wrapped in `IpAddr`.
+pub fn ip(name: &str, cidr: &str) -> Result<IpAddr> {
+    match IpNetwork::from_str(cidr)? {
+        IpNetwork::V4(net) => {
+            if net.prefix() == 32 {
+                return Err(Error::PrefixTooLong);
+            }
+            ip4(name, net).map(IpAddr::V4)
+        }
+        IpNetwork::V6(net) => {
             if net.prefix() == 128 {
-                return Err(format!("{}/{} is already a full IPv6 address",
-                                   net.ip(),
-                                   net.prefix()));
-            } else {
-                net
+                return Err(Error::PrefixTooLong);
             }
+            ip6(name, net).map(IpAddr::V6)
         }
-        Err(msg) => return Err(msg),
-    };
-    ip6(name, net)
-}
-
-fn ip6(name: &str, net: Ipv6Network) -> Result<Ipv6Addr, String> {
-    // If we divide the prefix by 4 we will get the total number
-    // of characters that we must never touch.
-    let network_len = net.prefix() as usize / 4;
-    let ip = net.ip().segments();
-    // Uncompress the IP address and throw away the semi-colons
-    // so we can easily join extract the network part and later
-    // join it to the address part that we will compute.
-    let ip_parts: Vec<String> = ip.iter()
-        .map(|b| format!("{:04x}", b))
-        .collect();
-    let ip_hash = ip_parts.join("");
-    let ip_hash = ip_hash.as_str();
-    let network_hash = &ip_hash[0..network_len];
-    // The number of characters we need to generate
-    //
-    // * An IPv6 address has a total number of 32 (8*4) characters.
-    // * Subtracting those characters from the total in an IP address
-    //   gives us the number of characters we need to generate.
-    let address_len = 32 - network_len;
-    // Blake2b generates hashes in multiples of 2 so we need to divide
-    // the total number of characters we need by 2. Sadly this means we
-    // can't always fully utilise the address space we need to fill.
-    let hash_is_bigger = address_len % 2 != 0;
-    let mut blake_len = address_len / 2;
-    if hash_is_bigger {
-        blake_len += 1;
+    }
+}
+
+fn ip6(name: &str, net: Ipv6Network) -> Result<Ipv6Addr> {
+    let host = host_part(name, u128::from(net.ip()), net.prefix(), 128);
+    Ok(Ipv6Addr::from(host))
+}
+
+fn ip4(name: &str, net: Ipv4Network) -> Result<Ipv4Addr> {
+    let host = host_part(name, u128::from(u32::from(net.ip())), net.prefix(), 32);
+    Ok(Ipv4Addr::from(host as u32))
+}
+
+/// Compute an address as a `bits`-wide integer by keeping the network
+/// portion of `network` and filling the remaining host bits from a
+/// Blake2b digest of `name`.
+///
+/// Working on the raw integer keeps the network bits exactly as the
+/// prefix describes them for every prefix length, including ones that
+/// are not nibble- or octet-aligned (`/52`, `/60`, `/70`, …), and uses
+/// the full host space: `(network & mask) | (host & !mask)`.
+fn host_part(name: &str, network: u128, prefix: u8, bits: u32) -> u128 {
+    let prefix = u32::from(prefix);
+    // `<< bits` would overflow, so a zero-length prefix masks to nothing.
+    let mask = if prefix == 0 {
+        0
+    } else {
+        (!0u128 << (bits - prefix)) & width_mask(bits)
     };
-    let hash = hash(name, blake_len);
-    let address_hash = if hash_is_bigger {
-        &hash[..hash.len()]
+    // Request just enough whole bytes to cover the host bits; any surplus
+    // high bits in the leading byte are discarded by `& !mask`.
+    let host_bits = bits - prefix;
+    let host_bytes = ((host_bits + 7) / 8) as usize;
+    let mut host = 0u128;
+    for byte in hash_bytes(name, host_bytes) {
+        host = (host << 8) | u128::from(byte);
+    }
+    (network & mask) | (host & !mask & width_mask(bits))
+}
+
+/// A mask of the low `bits` bits, guarding against a 128-bit shift.
+fn width_mask(bits: u32) -> u128 {
+    if bits >= 128 {
+        !0
     } else {
-        hash.as_str()
+        (1u128 << bits) - 1
+    }
+}
+
+/// Derives a child subnet of `cidr` for `name`.
+///
+/// Given a parent network such as `fd00::/48` and a longer `new_prefix`
+/// (say `64`), `subnet` hashes `name` to pick the bits that sit between
+/// the parent prefix and the new prefix and returns the resulting
+/// `Ipv6Network`. The same name always maps to the same child subnet,
+/// which can in turn host stable addresses via [`ip`].
+pub fn subnet(name: &str, cidr: &str, new_prefix: u8) -> Result<Ipv6Network> {
+    let net = Ipv6Network::from_str(cidr)?;
+    let parent = u32::from(net.prefix());
+    let new = u32::from(new_prefix);
+    if new <= parent || new > 128 {
+        return Err(Error::PrefixTooLong);
+    }
+    // The bits we get to choose live between the parent and new prefix.
+    let subnet_bits = new - parent;
+    let mut chosen = 0u128;
+    for byte in hash_bytes(name, ((subnet_bits + 7) / 8) as usize) {
+        chosen = (chosen << 8) | u128::from(byte);
+    }
+    let chosen = chosen & width_mask(subnet_bits);
+    // Keep the parent network bits and slot the chosen bits directly
+    // below them, leaving the host portion zeroed for a clean CIDR.
+    let parent_mask = if parent == 0 { 0 } else { !0u128 << (128 - parent) };
+    let network = u128::from(net.ip());
+    let child = (network & parent_mask) | (chosen << (128 - new));
+    Ipv6Network::new(Ipv6Addr::from(child), new_prefix).map_err(Error::from)
+}
+
+/// Generates an address for each name in `names` within `cidr`.
+///
+/// Returns `(name, IpAddr)` pairs in the same order as the input, which
+/// is handy for pre-populating DNS or firewall tables from a list of
+/// hostnames. The first name that fails to map aborts with its error.
+pub fn generate<I, S>(names: I, cidr: &str) -> Result<Vec<(S, IpAddr)>>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str>
+{
+    names.into_iter()
+        .map(|name| {
+            let addr = ip(name.as_ref(), cidr)?;
+            Ok((name, addr))
+        })
+        .collect()
+}
+
+/// Reports how many addresses a prefix can hold.
+///
+/// The count is the size of the host space (`2^(host bits)`), saturating
+/// at `u128::MAX` for a `::/0`-sized IPv6 prefix.
+pub fn capacity(cidr: &str) -> Result<u128> {
+    let net = IpNetwork::from_str(cidr)?;
+    let host_bits = total_bits(&net) - u32::from(net.prefix());
+    Ok(if host_bits >= 128 {
+        u128::max_value()
+    } else {
+        1u128 << host_bits
+    })
+}
+
+/// The outcome of checking a batch of names against a prefix.
+#[derive(Debug)]
+pub struct Collisions {
+    /// The number of addresses the prefix can hold.
+    pub capacity: u128,
+    /// The number of names in the batch.
+    pub requested: usize,
+    /// Addresses that more than one name mapped to.
+    pub clashes: Vec<IpAddr>,
+    /// The longest prefix that still has room for `requested` names, set
+    /// only when the batch collided in the current prefix. A prefix this
+    /// long or shorter gives the host space the batch needs.
+    pub suggested_prefix: Option<u8>,
+}
+
+/// Detects names that hash into the same address within `cidr`.
+///
+/// When the host space is small relative to the number of names, two
+/// distinct names can land on the same address. The returned
+/// [`Collisions`] lists the clashing addresses and, when there are any,
+/// the prefix length that would be short enough to fit the batch — so a
+/// caller can resize the network before deploying.
+pub fn collisions<I, S>(names: I, cidr: &str) -> Result<Collisions>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str>
+{
+    let net = IpNetwork::from_str(cidr)?;
+    let bits = total_bits(&net);
+    let pairs = generate(names, cidr)?;
+    let requested = pairs.len();
+    let mut seen: HashMap<IpAddr, usize> = HashMap::new();
+    let mut clashes = Vec::new();
+    for &(_, addr) in &pairs {
+        let count = seen.entry(addr).or_insert(0);
+        *count += 1;
+        if *count == 2 {
+            clashes.push(addr);
+        }
+    }
+    let suggested_prefix = if clashes.is_empty() {
+        None
+    } else {
+        Some(fitting_prefix(bits, requested))
     };
-    let ip_hash = format!("{}{}", network_hash, address_hash);
-    let ip = format!("{}:{}:{}:{}:{}:{}:{}:{}",
-                     &ip_hash[0..4],
-                     &ip_hash[4..8],
-                     &ip_hash[8..12],
-                     &ip_hash[12..16],
-                     &ip_hash[16..20],
-                     &ip_hash[20..24],
-                     &ip_hash[24..28],
-                     &ip_hash[28..32]);
-    Ipv6Addr::from_str(ip.as_str())
-        .map_err(|err| format!("generated IPv6 address ({}) has {}", ip, err))
-}
-
-// Calculate a hash for the subnet
-pub fn subnet(name: &str) -> String {
-    hash(name, 2)
-}
-
-fn hash(name: &str, len: usize) -> String {
+    Ok(Collisions {
+        capacity: capacity(cidr)?,
+        requested,
+        clashes,
+        suggested_prefix,
+    })
+}
+
+// The address width in bits for the network's family.
+fn total_bits(net: &IpNetwork) -> u32 {
+    match *net {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    }
+}
+
+// The longest prefix whose host space still holds `count` addresses.
+fn fitting_prefix(bits: u32, count: usize) -> u8 {
+    let mut host_bits = 0;
+    while host_bits < bits && (1u128 << host_bits) < count as u128 {
+        host_bits += 1;
+    }
+    (bits - host_bits) as u8
+}
+
+fn hash_bytes(name: &str, len: usize) -> Vec<u8> {
     let mut hash = Blake2b::new(len);
     hash.input_str(name);
-    hash.result_str()
+    let mut out = vec![0u8; len];
+    hash.result(&mut out);
+    out
 }
 
 #[cfg(test)]
 mod test {
+    use std::net::IpAddr;
+
     #[test]
     fn ip_is_valid() {
         match super::ip("c0a010fb-2632-40cb-a105-90297cba567a",
                          "fd52:f6b0:3162::/48") {
-            Ok(_) => {
-                // yay!
-            }
-            Err(err) => panic!(err),
+            Ok(addr) => assert!(addr.is_ipv6()),
+            Err(err) => panic!("{}", err),
         };
     }
 
+    #[test]
+    fn ipv4_is_valid() {
+        match super::ip("c0a010fb-2632-40cb-a105-90297cba567a", "10.0.0.0/8") {
+            Ok(IpAddr::V4(addr)) => assert_eq!(addr.octets()[0], 10),
+            Ok(_) => panic!("expected an IPv4 address"),
+            Err(err) => panic!("{}", err),
+        };
+    }
+
+    #[test]
+    fn subnet_is_within_parent_and_stable() {
+        let name = "c0a010fb-2632-40cb-a105-90297cba567a";
+        let child = super::subnet(name, "fd00::/48", 64).unwrap();
+        assert_eq!(child.prefix(), 64);
+        // The parent's /48 bits must survive and the name must be stable.
+        assert!(child.to_string().starts_with("fd00:"));
+        assert_eq!(child, super::subnet(name, "fd00::/48", 64).unwrap());
+    }
+
+    #[test]
+    fn batch_collides_in_a_tiny_prefix() {
+        // A /126 IPv6 space only has 4 hosts, so eight names are
+        // guaranteed to clash.
+        let names = vec!["a", "b", "c", "d", "e", "f", "g", "h"];
+        let report = super::collisions(names, "fd00::/126").unwrap();
+        assert_eq!(report.capacity, 4);
+        assert_eq!(report.requested, 8);
+        assert!(!report.clashes.is_empty());
+        assert!(report.suggested_prefix.unwrap() <= 126);
+    }
 }